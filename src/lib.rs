@@ -1,19 +1,56 @@
+extern crate flate2;
+
 use std::error::Error;
 use std::{io, fs};
 use std::io::{BufReader, BufRead};
 use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+
+use flate2::read::MultiGzDecoder;
 
 include!("splitline.rs");
 
 type LineIterator = Iterator<Item=io::Result<String>>;
 
 pub struct JoinConfig {
-    pub left: JoinFileConfig,
-    pub right: JoinFileConfig,
+    pub files: Vec<JoinFileConfig>,
     pub output: OutputOrder,
-    pub output_fn: fn(String) -> (),
+    // Returns Err on a write failure (e.g. a broken pipe when the consumer
+    // closes early, as with `| head`) so join() can stop cleanly instead
+    // of panicking the way println! would.
+    pub output_fn: fn(String) -> io::Result<()>,
     pub delim: String,
     pub has_header: bool,
+    pub strategy: JoinStrategy,
+    // Assume non-driver files have no repeated keys, skipping the buffered
+    // many-to-many group handling (mirrors cdx's --quick).
+    pub quick: bool,
+    pub comparator: KeyComparator,
+    // cdx-style poly join: emit output for every key seen in any file
+    // (merging present fields, filling absent ones with their `missing`
+    // placeholder) instead of emitting a combined line only for keys that
+    // every file has. A key repeated within a file still yields one line per
+    // combination of rows.
+    pub poly: bool,
+}
+
+// How two key fields are compared against each other. Merge join assumes
+// every input is already sorted according to this same ordering, and
+// panics on the first row that is out of order under it.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum KeyComparator {
+    Raw,
+    IgnoreCase,
+    Numeric,
+}
+
+// How the inputs are matched up. Merge is the classic sorted-merge join
+// and requires every input to already be sorted by key; Hash trades that
+// requirement for slurping all but the first file into memory.
+#[derive(Debug,Clone,Copy,PartialEq)]
+pub enum JoinStrategy {
+    Merge,
+    Hash,
 }
 
 #[derive(Debug,Clone)]
@@ -33,39 +70,54 @@ pub struct JoinFileConfig {
 #[derive(Debug)]
 pub enum OutputField {
     JoinField,
-    // File should be 1 or 2; field should be 0-indexed
+    // `file` is the 1-based index into JoinConfig.files; `field` is 0-indexed.
     FileField { file: usize, field: usize },
     NamedFileField { file: usize, field: String },
 }
 
 #[derive(Debug)]
 pub enum OutputOrder {
-    // Key, plus all other fields from file1, then file2 (GNU Join default)
+    // Key, plus all other fields from file 1, then file 2 (GNU Join
+    // default). Only meaningful for exactly 2 input files.
     GnuDefault,
     // Similar except the same number of fields are output for each line
     Auto,
     Explicit(Vec<OutputField>),
 }
 
-struct JoinFile<'a> {
-    config: &'a JoinFileConfig,
+struct JoinFile {
     lines: Box<LineIterator>,
     eof: bool,
     row: SplitLine,
-    printed: bool,
     next_row: SplitLine,
     num_fields: usize,
     header: Option<Vec<String>>,
     key_fields: Vec<usize>,
 }
 
-impl<'a> JoinFile<'a> {
+impl JoinFile {
     pub fn new(config: &JoinFileConfig) -> Result<JoinFile, Box<Error>> {
 
+        // Transparently decompress gzip input, detected either by a `.gz`
+        // filename or (so that e.g. `zcat file.gz | rjoin - other` also
+        // works) by peeking the gzip magic bytes on the stream itself.
         fn open_file(filename: &str) -> Result<Box<io::Read>, Box<Error>> {
-            Ok(match filename {
+            let raw : Box<io::Read> = match filename {
                 "-" => Box::new(io::stdin()),
                 _   => Box::new(fs::File::open(filename)?),
+            };
+
+            let mut reader = BufReader::new(raw);
+            let is_gzip = filename.ends_with(".gz") || {
+                let peek = reader.fill_buf()?;
+                peek.len() >= 2 && peek[0] == 0x1f && peek[1] == 0x8b
+            };
+
+            Ok(if is_gzip {
+                Box::new(MultiGzDecoder::new(reader))
+            }
+            else {
+                Box::new(reader)
             })
         }
 
@@ -74,10 +126,8 @@ impl<'a> JoinFile<'a> {
             let iter = Box::new(BufReader::new(h).lines());
 
             JoinFile {
-                config: config,
                 lines: iter,
                 eof: false,
-                printed: false,
                 row: SplitLine::new("".into(), '\t', vec![]),
                 next_row: SplitLine::new("".into(), '\t', vec![]),
                 num_fields: 0,
@@ -118,7 +168,6 @@ impl<'a> JoinFile<'a> {
             return false;
         }
         self.row = self.next_row.clone();
-        self.printed = false;
 
         // This sets .eof = true, which will cause the next call to fail.
         // XXX we actually want this to call std::mem::replace and overwrite next_row/next_key
@@ -147,135 +196,305 @@ impl<'a> JoinFile<'a> {
             },
         }
     }
-} // impl JoinFile 
-
-pub fn join(mut config: JoinConfig) -> Result<(), Box<Error>> {
-    let mut left = &mut JoinFile::new(&config.left)?;
-    let mut right = &mut JoinFile::new(&config.right)?;
+} // impl JoinFile
+
+// Resolve a file's configured key fields (which may be named columns,
+// requiring --header) into concrete column indexes.
+fn resolve_key_fields(key_fields: &KeyFields, header: &Option<Vec<String>>) -> Vec<usize> {
+    key_fields.iter().map(|key| match *key {
+        KeyField::Indexed(i) => i,
+        KeyField::Named(ref s) => {
+            let header = header.as_ref().unwrap_or_else(|| panic!("named fields require --header"));
+            header.iter().position(|h| h == s)
+                .unwrap_or_else(|| panic!("named column '{}' not found", s))
+        },
+    }).collect()
+}
 
-    if config.has_header {
-        left.read_header(&config.delim);
-        right.read_header(&config.delim);
+// Resolve any NamedFileField entries in an explicit output order into
+// FileField entries, using the appropriate file's header.
+fn resolve_named_output_fields(fields: &mut [OutputField], headers: &[Option<Vec<String>>]) {
+    for item in fields.iter_mut() {
+        let resolved = if let OutputField::NamedFileField { file, ref field } = *item {
+            let header = headers[file - 1].as_ref().unwrap_or_else(|| panic!("named fields require --header"));
+            let index = header.iter().position(|h| h == field)
+                .unwrap_or_else(|| panic!("named column '{}' not found", field));
+            Some(OutputField::FileField { file, field: index })
+        }
+        else {
+            None
+        };
+        if let Some(resolved) = resolved {
+            *item = resolved;
+        }
     }
+}
 
-    if !left.first_fill() {
-        panic!("No input found on left side");
+// If the output order is still 'auto', resolve it to an explicit column
+// list now that we know how many fields each file has: the key, then every
+// non-key field from file 1, then every non-key field from file 2, etc.
+fn resolve_auto_output(output: &mut OutputOrder, num_fields: &[usize], key_fields: &[Vec<usize>]) {
+    if let OutputOrder::Auto = *output {
+        let mut v = vec![OutputField::JoinField];
+        for (i, &n) in num_fields.iter().enumerate() {
+            for field in 0..n {
+                if !key_fields[i].contains(&field) {
+                    v.push(OutputField::FileField { file: i + 1, field });
+                }
+            }
+        }
+        *output = OutputOrder::Explicit(v);
     }
-    if !right.first_fill() {
-        panic!("No input found on right side");
+}
+
+pub fn join(config: JoinConfig) -> Result<(), Box<Error>> {
+    if config.files.len() < 2 {
+        panic!("join requires at least 2 input files");
     }
 
-    // If using Auto output order, update it to Explicit now we know the
-    // number of columns in each file.
-    if let OutputOrder::Auto = config.output {
-        let mut v = vec![];
-        v.push(OutputField::JoinField);
-        let mut file = 1;
-        for f in vec![&left, &right] {
-            for field in 0..f.num_fields {
-                if let None = f.row.key_fields.iter().find(|&&i| i == field) {
-                    v.push(OutputField::FileField { file, field });
-                }
+    let result = match config.strategy {
+        JoinStrategy::Merge => merge_join(config),
+        JoinStrategy::Hash => hash_join(config),
+    };
+
+    // A consumer closing the output pipe early (e.g. `| head`) isn't an
+    // application error - stop quietly instead of reporting it as one.
+    if let Err(ref e) = result {
+        if let Some(io_err) = e.downcast_ref::<io::Error>() {
+            if io_err.kind() == io::ErrorKind::BrokenPipe {
+                return Ok(());
             }
-            file += 1;
         }
-        config.output = OutputOrder::Explicit(v);
     }
+    result
+}
+
+fn merge_join(mut config: JoinConfig) -> Result<(), Box<Error>> {
+    let mut files = Vec::with_capacity(config.files.len());
+    for fc in &config.files {
+        files.push(JoinFile::new(fc)?);
+    }
+    let n = files.len();
 
-    // This ugly code exists to convert named fields to indexes before
-    // running. Only works if we have a header
     if config.has_header {
-        fn lookup_index(s: &str, v: Vec<String>) -> usize {
-            match v.iter().position(|i| *i == s) {
-                Some(index) => index,
-                None => panic!("named column '{}' not found", s),
-            }
+        for f in &mut files {
+            f.read_header(&config.delim);
         }
+    }
 
-        // Convert named key/output fields to column indexes
-        for f in vec![&left, &right] {
-            for key in f.config.key_fields {
-                if let KeyField::Named(s) = key {
-                    key = KeyField::Indexed( lookup_index(&s, f.header.unwrap()) );
-                }
-            }
+    for (i, f) in files.iter_mut().enumerate() {
+        f.key_fields = resolve_key_fields(&config.files[i].key_fields, &f.header);
+    }
+
+    for (i, f) in files.iter_mut().enumerate() {
+        if !f.first_fill() {
+            panic!("No input found on file {}", i + 1);
         }
-        if let OutputOrder::Explicit(cols) = config.output {
-            for col in cols {
-                if let OutputField::NamedFileField { file, field } = col {
-                    let f : *const JoinFile = if file == 1 { left } else { right };
-                    col = OutputField::FileField {
-                        file: file,
-                        field: lookup_index(&field, unsafe { (*f).header.unwrap() }),
-                    };
+    }
+
+    let num_fields: Vec<usize> = files.iter().map(|f| f.num_fields).collect();
+    let key_fields: Vec<Vec<usize>> = files.iter().map(|f| f.key_fields.clone()).collect();
+    resolve_auto_output(&mut config.output, &num_fields, &key_fields);
+
+    if let OutputOrder::Explicit(ref mut cols) = config.output {
+        let headers: Vec<Option<Vec<String>>> = files.iter().map(|f| f.header.clone()).collect();
+        resolve_named_output_fields(cols, &headers);
+    }
+
+    if config.has_header {
+        do_header_output(&config, &files)?;
+    }
+
+    // Merge join requires every input be pre-sorted according to
+    // config.comparator; check_sorted panics with a clear message the
+    // moment that assumption is violated, rather than silently producing a
+    // mismatched join.
+    let mut last_keys: Vec<Option<Vec<String>>> = vec![None; n];
+    let mut more: Vec<bool> = vec![true; n];
+
+    while more.iter().any(|&m| m) {
+        let min_idx = (0..n).filter(|&i| more[i])
+            .min_by(|&a, &b| compare_keys(&files[a].row.keys(), &files[b].row.keys(), config.comparator))
+            .unwrap();
+        let min_key_owned: Vec<String> = files[min_idx].row.keys().iter().map(|s| (*s).to_owned()).collect();
+        let min_key: Vec<&str> = min_key_owned.iter().map(|s| s.as_str()).collect();
+
+        // Buffer every row (on every file) sharing this key, advancing each
+        // matching file's cursor past its share of the group.
+        let mut groups: Vec<Vec<SplitLine>> = vec![vec![]; n];
+        for i in 0..n {
+            if !more[i] || compare_keys(&files[i].row.keys(), &min_key, config.comparator) != Ordering::Equal {
+                continue;
+            }
+            loop {
+                check_sorted(&mut last_keys[i], &files[i].row.keys(), config.comparator, i + 1);
+                groups[i].push(files[i].row.clone());
+                if config.quick && i > 0 {
+                    more[i] = files[i].refill();
+                    break;
+                }
+                if !files[i].refill() {
+                    more[i] = false;
+                    break;
+                }
+                if compare_keys(&files[i].row.keys(), &min_key, config.comparator) != Ordering::Equal {
+                    break;
                 }
             }
         }
 
-        // Now we've normalized the output order, print the header
-        do_header_output(&config, left, right);
+        emit_key(&config, &groups)?;
     }
 
-    // Populate f.key_fields (ugly...)
-    for f in vec![&mut left, &mut right] {
-        f.key_fields = f.config.key_fields.iter().map(|x| {
-            match *x {
-                KeyField::Indexed(s) => s,
-                KeyField::Named(_) => panic!("named fields require --header"),
-            }
-        }).collect();
+    Ok(())
+}
+
+// Hash join: slurp every file but the first into memory keyed by its join
+// key, then stream the first ("driver") file and look up matches. Doesn't
+// require any input to be sorted, at the cost of buffering every
+// non-driver file.
+fn hash_join(mut config: JoinConfig) -> Result<(), Box<Error>> {
+    let mut files = Vec::with_capacity(config.files.len());
+    for fc in &config.files {
+        files.push(JoinFile::new(fc)?);
     }
+    let n = files.len();
 
-    // Loop through the inputs
-    let mut todo = true;
-    while todo {
-        match compare_keys(&left.row.keys(), &right.row.keys()) {
-            Ordering::Equal => {
-                do_output(&config, left, right, true, true);
-                todo = smart_refill(left, right);
-            },
-            Ordering::Less => {
-                if left.config.all && !left.printed {
-                    do_output(&config, left, right, true, false);
-                }
-                todo = left.refill();
-            },
-            Ordering::Greater => {
-                if right.config.all && !right.printed {
-                    do_output(&config, left, right, false, true);
-                }
-                todo = right.refill();
-            },
-        };
+    if config.has_header {
+        for f in &mut files {
+            f.read_header(&config.delim);
+        }
+    }
+
+    for (i, f) in files.iter_mut().enumerate() {
+        f.key_fields = resolve_key_fields(&config.files[i].key_fields, &f.header);
+    }
+
+    for (i, f) in files.iter_mut().enumerate() {
+        if !f.first_fill() {
+            panic!("No input found on file {}", i + 1);
+        }
     }
 
-    // Print the last if all (normally this would happen on refill)
-    if left.config.all && !left.printed {
-        do_output(&config, left, right, true, false);
+    let num_fields: Vec<usize> = files.iter().map(|f| f.num_fields).collect();
+    let key_fields: Vec<Vec<usize>> = files.iter().map(|f| f.key_fields.clone()).collect();
+    resolve_auto_output(&mut config.output, &num_fields, &key_fields);
+
+    if let OutputOrder::Explicit(ref mut cols) = config.output {
+        let headers: Vec<Option<Vec<String>>> = files.iter().map(|f| f.header.clone()).collect();
+        resolve_named_output_fields(cols, &headers);
     }
-    if right.config.all && !right.printed {
-        do_output(&config, left, right, false, true);
+
+    if config.has_header {
+        do_header_output(&config, &files)?;
     }
 
-    // Finish off the remaining unpairable lines
-    if !left.eof && left.config.all {
-        while left.refill() {
-            do_output(&config, left, right, true, false);
+    let mut maps: Vec<HashMap<Vec<String>, Vec<SplitLine>>> = (0..n - 1).map(|_| HashMap::new()).collect();
+    for i in 1..n {
+        loop {
+            let key = normalize_key(&files[i].row.keys(), config.comparator);
+            maps[i - 1].entry(key).or_insert_with(Vec::new).push(files[i].row.clone());
+            if !files[i].refill() {
+                break;
+            }
+        }
+    }
+
+    // Tracks which keys of each non-driver map were actually visited while
+    // streaming the driver, so unmatched entries can be reported below for
+    // any non-driver file marked `all`.
+    let mut matched: Vec<HashSet<Vec<String>>> = (0..n - 1).map(|_| HashSet::new()).collect();
+
+    loop {
+        let key = normalize_key(&files[0].row.keys(), config.comparator);
+
+        let mut groups: Vec<Vec<SplitLine>> = Vec::with_capacity(n);
+        groups.push(vec![files[0].row.clone()]);
+        for (j, map) in maps.iter().enumerate() {
+            match map.get(&key) {
+                Some(rows) => {
+                    matched[j].insert(key.clone());
+                    groups.push(rows.clone());
+                },
+                None => groups.push(Vec::new()),
+            }
+        }
+
+        emit_key(&config, &groups)?;
+
+        if !files[0].refill() {
+            break;
         }
     }
-    else if !right.eof && right.config.all {
-        while right.refill() {
-            do_output(&config, left, right, false, true);
+
+    // Any non-driver file marked `all` (or every file, under --poly, which
+    // emits every key regardless of `all`) still needs its never-visited
+    // keys reported, same as the driver's own unmatched rows are handled
+    // inside emit_key above.
+    for (j, map) in maps.iter().enumerate() {
+        let i = j + 1;
+        if !config.poly && !config.files[i].all {
+            continue;
+        }
+        for (key, rows) in map {
+            if matched[j].contains(key) {
+                continue;
+            }
+            let mut groups: Vec<Vec<SplitLine>> = vec![Vec::new(); n];
+            if config.poly {
+                // Emit the key once, merged across every non-driver file
+                // holding it, from the first map that has it.
+                if maps[..j].iter().any(|m| m.contains_key(key)) {
+                    continue;
+                }
+                for (k, m) in maps.iter().enumerate().skip(j) {
+                    if let Some(r) = m.get(key) {
+                        groups[k + 1] = r.clone();
+                    }
+                }
+            }
+            else {
+                groups[i] = rows.clone();
+            }
+            emit_key(&config, &groups)?;
         }
     }
 
     Ok(())
 }
 
-fn compare_keys(left: &Vec<&str>, right: &Vec<&str>) -> Ordering {
+// Merge join requires every input be pre-sorted according to `comparator`;
+// track the last key seen on one file and panic with a clear message the
+// moment a row arrives out of order, rather than silently mismatching.
+fn check_sorted(last: &mut Option<Vec<String>>, current: &[&str], comparator: KeyComparator, file: usize) {
+    let current_owned: Vec<String> = current.iter().map(|s| (*s).to_owned()).collect();
+    if let Some(ref prev) = *last {
+        let prev_refs: Vec<&str> = prev.iter().map(|s| s.as_str()).collect();
+        if compare_keys(current, &prev_refs, comparator) == Ordering::Less {
+            panic!("file {} input is not sorted by key (merge join requires consistently sorted input; pass --hash for unsorted input)", file);
+        }
+    }
+    *last = Some(current_owned);
+}
+
+fn compare_key_field(left: &str, right: &str, comparator: KeyComparator) -> Ordering {
+    match comparator {
+        KeyComparator::Raw => left.cmp(right),
+        KeyComparator::IgnoreCase => left.to_ascii_lowercase().cmp(&right.to_ascii_lowercase()),
+        KeyComparator::Numeric => {
+            match (left.parse::<f64>(), right.parse::<f64>()) {
+                (Ok(l), Ok(r)) => l.partial_cmp(&r).unwrap_or(Ordering::Equal),
+                // Fall back to string comparison if either side isn't numeric.
+                _ => left.cmp(right),
+            }
+        },
+    }
+}
+
+fn compare_keys(left: &[&str], right: &[&str], comparator: KeyComparator) -> Ordering {
     let mut result = Ordering::Equal;
     for i in 0..left.len() {
-        result = left[i].cmp(right[i]);
+        result = compare_key_field(left[i], right[i], comparator);
         if result != Ordering::Equal {
             break
         }
@@ -283,23 +502,35 @@ fn compare_keys(left: &Vec<&str>, right: &Vec<&str>) -> Ordering {
     result
 }
 
-fn do_header_output(config: &JoinConfig, left: &JoinFile, right: &JoinFile) {
+// Canonicalize a key so that values the comparator treats as equal (e.g.
+// "Foo"/"foo" under --ignore-case, or "7"/"7.0" under --numeric) also
+// compare equal as HashMap keys.
+fn normalize_key(key: &[&str], comparator: KeyComparator) -> Vec<String> {
+    key.iter().map(|field| match comparator {
+        KeyComparator::Raw => (*field).to_owned(),
+        KeyComparator::IgnoreCase => field.to_ascii_lowercase(),
+        KeyComparator::Numeric => match field.parse::<f64>() {
+            Ok(n) => n.to_string(),
+            Err(_) => (*field).to_owned(),
+        },
+    }).collect()
+}
+
+fn do_header_output(config: &JoinConfig, files: &[JoinFile]) -> Result<(), Box<Error>> {
     if let OutputOrder::Explicit(ref fields) = config.output {
         // This function is only called if headers are set.
-        let left_header = left.header.as_ref().unwrap();
-        let right_header = right.header.as_ref().unwrap();
+        let headers: Vec<&Vec<String>> = files.iter().map(|f| f.header.as_ref().unwrap()).collect();
 
-        let mut cols : Vec<&str> = vec![];
+        let mut cols: Vec<&str> = vec![];
         for item in fields {
             match *item {
                 OutputField::JoinField => {
-                    for index in &left.row.key_fields {
-                        cols.push( left_header[*index].as_str() );  
+                    for index in &files[0].key_fields {
+                        cols.push(headers[0][*index].as_str());
                     }
                 },
                 OutputField::FileField { file, field } => {
-                    let f = if file == 1 { left_header } else { right_header };
-                    cols.push(f[field].as_str());
+                    cols.push(headers[file - 1][field].as_str());
                 },
                 OutputField::NamedFileField { ref field, .. } => {
                     //XXX should this appear at all?
@@ -307,36 +538,106 @@ fn do_header_output(config: &JoinConfig, left: &JoinFile, right: &JoinFile) {
                 },
             }
         }
-        (config.output_fn)(cols.join(&config.delim));
+        (config.output_fn)(cols.join(&config.delim))?;
+        Ok(())
     }
     else {
         panic!("gnudefault order doesn't support --header yet");
     }
 }
 
-fn do_output(config: &JoinConfig, left: &mut JoinFile, right: &mut JoinFile,
-             print_left: bool, print_right: bool) {
+// Given one buffered row-group per file for a given key (an empty Vec
+// means that file had no row with this key), decide what rows (if any) to
+// emit, then print them. `groups[i]` holds every row file i contributed to
+// this key - more than one only when that file has repeated keys.
+fn emit_key(config: &JoinConfig, groups: &[Vec<SplitLine>]) -> Result<(), Box<Error>> {
+    let n = groups.len();
+    let present: Vec<bool> = groups.iter().map(|g| !g.is_empty()).collect();
+
+    if config.poly {
+        // Same cartesian expansion as the non-poly match below - a repeated
+        // key in any present file still needs every one of its rows emitted.
+        for combo in cartesian_rows(groups, &present) {
+            do_output(config, &combo, &present)?;
+        }
+        return Ok(());
+    }
 
-    if print_left {
-        left.printed = true;
+    // The key only "matches" if every file has a row for it - `all` only
+    // controls whether an unmatched file's rows are printed standalone
+    // below, not whether it's exempt from forming a combined row here.
+    let required_ok = present.iter().all(|&p| p);
+
+    if required_ok {
+        for combo in cartesian_rows(groups, &present) {
+            do_output(config, &combo, &present)?;
+        }
     }
-    if print_right {
-        right.printed = true;
+    else {
+        // The required set didn't match, so no combined row is emitted -
+        // but any `all` file that did have a row for this key still prints
+        // it standalone, with every other file filled in as missing.
+        for i in 0..n {
+            if !config.files[i].all || !present[i] {
+                continue;
+            }
+            let mut solo_present = vec![false; n];
+            solo_present[i] = true;
+            for row in &groups[i] {
+                let rows: Vec<SplitLine> = (0..n).map(|j| {
+                    if j == i { row.clone() } else { SplitLine::new("".into(), '\t', vec![]) }
+                }).collect();
+                do_output(config, &rows, &solo_present)?;
+            }
+        }
     }
 
-    let mut keys : Vec<&str> = if print_left { left.row.keys() } else { right.row.keys() };
+    Ok(())
+}
+
+// Cartesian product of the present files' row-groups, e.g. a key repeated
+// twice in file 1 and three times in file 2 yields all 6 pairs instead of
+// the 2 (or 3) a naive advance-one-cursor join would give. Absent files
+// contribute a single placeholder slot (its content is never read, since
+// callers check `present` before reading a row's fields).
+fn cartesian_rows(groups: &[Vec<SplitLine>], present: &[bool]) -> Vec<Vec<SplitLine>> {
+    let n = groups.len();
+    let mut result = vec![vec![SplitLine::new("".into(), '\t', vec![]); n]];
+    for i in 0..n {
+        if !present[i] {
+            continue;
+        }
+        let mut next = Vec::new();
+        for combo in &result {
+            for row in &groups[i] {
+                let mut c = combo.clone();
+                c[i] = row.clone();
+                next.push(c);
+            }
+        }
+        result = next;
+    }
+    result
+}
+
+// Print one output line for a key, given the (possibly placeholder) current
+// row for every file and which of those rows are actually present/matched.
+fn do_output(config: &JoinConfig, rows: &[SplitLine], present: &[bool]) -> Result<(), Box<Error>> {
+    let key_file = present.iter().position(|&p| p).expect("do_output called with no present files");
+    let mut keys: Vec<&str> = rows[key_file].keys();
 
-    let vals : Vec<&str> = match config.output {
+    let vals: Vec<&str> = match config.output {
         OutputOrder::GnuDefault => {
-            // Output join field, then remaining fields from left, then right
-            // Output blank fields as appropriate
+            if rows.len() != 2 {
+                panic!("gnudefault output order only supports exactly 2 input files");
+            }
+            // Output join field, then remaining fields from file 1, then file 2
             let mut v = vec![];
             v.append(&mut keys);
-            if print_left {
-                v.append( &mut left.row.fields_except_keys() );
-            }
-            if print_right {
-                v.append( &mut right.row.fields_except_keys() );
+            for (i, row) in rows.iter().enumerate() {
+                if present[i] {
+                    v.append(&mut row.fields_except_keys());
+                }
             }
             v
         },
@@ -349,21 +650,19 @@ fn do_output(config: &JoinConfig, left: &mut JoinFile, right: &mut JoinFile,
                         v.append(&mut keys);
                     },
                     OutputField::FileField { file, field } => {
-                        let f : *const JoinFile = if file == 1 { left } else { right };
-                        v.push(unsafe {
-                            if (file == 1 && print_left) || (file == 2 && print_right) {
-                                // File is joined, but might still be missing a trailing field
-                                if field < (*f).row.num_fields() {
-                                    (*f).row.field(field)
-                                }
-                                else {
-                                    ""
-                                }
+                        let i = file - 1;
+                        v.push(if present[i] {
+                            // File is joined, but might still be missing a trailing field
+                            if field < rows[i].num_fields() {
+                                rows[i].field(field)
                             }
                             else {
-                                // File is not joined, so use missing value
-                                &(*f).config.missing
+                                ""
                             }
+                        }
+                        else {
+                            // File is not joined, so use missing value
+                            config.files[i].missing.as_str()
                         });
                     },
                     OutputField::NamedFileField { .. } => {
@@ -375,27 +674,158 @@ fn do_output(config: &JoinConfig, left: &mut JoinFile, right: &mut JoinFile,
         },
         OutputOrder::Auto => panic!("invalid OutputOrder, this is a bug."),
     };
-    (config.output_fn)(vals.join(&config.delim));
-
+    (config.output_fn)(vals.join(&config.delim))?;
+    Ok(())
 }
 
-// Both left and right match, decide which one to refill first
-#[cfg_attr(feature="cargo-clippy", allow(if_same_then_else))]
-fn smart_refill(left: &mut JoinFile, right: &mut JoinFile) -> bool {
-    if left.eof {
-        right.refill()
+// Named `join_tests` (rather than `tests`) since splitline.rs's own
+// `#[cfg(test)] mod tests` is pulled into this same scope via `include!`
+// above.
+#[cfg(test)]
+mod join_tests {
+    use super::*;
+    use std::io::Write;
+
+    thread_local! {
+        static CAPTURED: std::cell::RefCell<Vec<String>> = const { std::cell::RefCell::new(Vec::new()) };
     }
-    else if right.eof {
-        left.refill()
+
+    // `output_fn` is a plain fn pointer (can't capture), so tests collect
+    // lines through a thread-local instead.
+    fn capture(s: String) -> io::Result<()> {
+        CAPTURED.with(|c| c.borrow_mut().push(s));
+        Ok(())
     }
-    else {
-        match compare_keys(&left.next_row.keys(), &right.next_row.keys()) {
-            Ordering::Equal => {
-                left.refill() && right.refill()
-            },
-            Ordering::Less => { left.refill() },
-            Ordering::Greater => { right.refill() },
+
+    fn write_temp(name: &str, content: &str) -> String {
+        let mut path = std::env::temp_dir();
+        path.push(format!("rjoin_join_tests_{}_{}", std::process::id(), name));
+        let mut f = fs::File::create(&path).unwrap();
+        f.write_all(content.as_bytes()).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+
+    fn file_config(path: &str, all: bool) -> JoinFileConfig {
+        JoinFileConfig {
+            filename: path.to_owned(),
+            key_fields: vec![KeyField::Indexed(0)],
+            all: all,
+            missing: String::new(),
         }
     }
-}
 
+    fn run_join(files: Vec<JoinFileConfig>, strategy: JoinStrategy) -> Vec<String> {
+        run_join_poly(files, strategy, false)
+    }
+
+    fn run_join_poly(files: Vec<JoinFileConfig>, strategy: JoinStrategy, poly: bool) -> Vec<String> {
+        CAPTURED.with(|c| c.borrow_mut().clear());
+        let config = JoinConfig {
+            files: files,
+            output: OutputOrder::Auto,
+            output_fn: capture,
+            delim: "\t".to_owned(),
+            has_header: false,
+            strategy: strategy,
+            quick: false,
+            comparator: KeyComparator::Raw,
+            poly: poly,
+        };
+        join(config).unwrap();
+        CAPTURED.with(|c| c.borrow().clone())
+    }
+
+    #[test]
+    fn merge_join_many_to_many_group() {
+        let left = write_temp("left_mtm", "a\tL1\na\tL2\n");
+        let right = write_temp("right_mtm", "a\tR1\na\tR2\na\tR3\n");
+
+        let lines = run_join(
+            vec![file_config(&left, false), file_config(&right, false)],
+            JoinStrategy::Merge,
+        );
+
+        assert_eq!(lines, vec![
+            "a\tL1\tR1", "a\tL1\tR2", "a\tL1\tR3",
+            "a\tL2\tR1", "a\tL2\tR2", "a\tL2\tR3",
+        ]);
+    }
+
+    #[test]
+    fn hash_and_merge_join_agree_on_all_semantics() {
+        let left = write_temp("left_all", "1\tA\n3\tC\n4\tD\n");
+        let right = write_temp("right_all", "1\tX\n2\tY\n4\tZ\n");
+
+        // -a 1: left's unmatched rows print standalone, right's don't.
+        let merge = run_join(vec![file_config(&left, true), file_config(&right, false)], JoinStrategy::Merge);
+        let hash = run_join(vec![file_config(&left, true), file_config(&right, false)], JoinStrategy::Hash);
+
+        assert_eq!(merge, vec!["1\tA\tX", "3\tC\t", "4\tD\tZ"]);
+
+        let mut merge_sorted = merge.clone();
+        merge_sorted.sort();
+        let mut hash_sorted = hash.clone();
+        hash_sorted.sort();
+        assert_eq!(merge_sorted, hash_sorted);
+    }
+
+    #[test]
+    fn hash_join_emits_unmatched_rows_from_non_driver_all_file() {
+        let left = write_temp("left_all2", "1\tA\n3\tC\n4\tD\n");
+        let right = write_temp("right_all2", "1\tX\n2\tY\n4\tZ\n");
+
+        // -a 1 -a 2: both sides' unmatched rows should print standalone,
+        // including the non-driver (right) file's unmatched key.
+        let merge = run_join(vec![file_config(&left, true), file_config(&right, true)], JoinStrategy::Merge);
+        let hash = run_join(vec![file_config(&left, true), file_config(&right, true)], JoinStrategy::Hash);
+
+        let mut merge_sorted = merge.clone();
+        merge_sorted.sort();
+        let mut hash_sorted = hash.clone();
+        hash_sorted.sort();
+
+        assert_eq!(merge_sorted, vec!["1\tA\tX", "2\t\tY", "3\tC\t", "4\tD\tZ"]);
+        assert_eq!(merge_sorted, hash_sorted);
+    }
+
+    #[test]
+    fn poly_emits_cartesian_product_for_a_repeated_key() {
+        let left = write_temp("left_poly_rep", "a\tL1\na\tL2\n");
+        let right = write_temp("right_poly_rep", "a\tR1\n");
+
+        let lines = run_join_poly(
+            vec![file_config(&left, false), file_config(&right, false)],
+            JoinStrategy::Merge,
+            true,
+        );
+
+        assert_eq!(lines, vec!["a\tL1\tR1", "a\tL2\tR1"]);
+    }
+
+    #[test]
+    fn hash_poly_emits_keys_unique_to_a_non_driver_file() {
+        let left = write_temp("left_hash_poly", "a\tL1\n");
+        let right = write_temp("right_hash_poly", "a\tR1\nb\tR2\n");
+
+        // Neither file is marked `all`, so only --poly should make the
+        // right-only `b` key appear.
+        let merge = run_join_poly(
+            vec![file_config(&left, false), file_config(&right, false)],
+            JoinStrategy::Merge,
+            true,
+        );
+        let hash = run_join_poly(
+            vec![file_config(&left, false), file_config(&right, false)],
+            JoinStrategy::Hash,
+            true,
+        );
+
+        let mut merge_sorted = merge.clone();
+        merge_sorted.sort();
+        let mut hash_sorted = hash.clone();
+        hash_sorted.sort();
+
+        assert_eq!(merge_sorted, vec!["a\tL1\tR1", "b\t\tR2"]);
+        assert_eq!(merge_sorted, hash_sorted);
+    }
+}
@@ -1,11 +1,13 @@
 #[macro_use]
 extern crate clap;
 extern crate rjoin;
+use clap::Arg;
 use std::process;
 use std::error::Error;
+use std::io;
 use std::io::Write;
 
-use rjoin::{JoinFileConfig, JoinConfig, OutputField, OutputOrder, KeyField, KeyFields};
+use rjoin::{JoinFileConfig, JoinConfig, JoinStrategy, KeyComparator, OutputField, OutputOrder, KeyField, KeyFields};
 
 fn main() {
     let mut stderr = std::io::stderr();
@@ -21,75 +23,139 @@ fn main() {
     }
 }
 
-// Default handler for join output lines
-fn println(s: String) -> () {
-    println!("{}", s);
+// Default handler for join output lines. Returns Err(BrokenPipe) instead of
+// panicking (as println! does) when the consumer closes the pipe early.
+fn println(s: String) -> io::Result<()> {
+    writeln!(io::stdout(), "{}", s)
 }
 
 fn setup() -> Result<JoinConfig, Box<Error>> {
+    // -k/-a/-m are repeatable, but must each take exactly one value per
+    // occurrence (number_of_values(1)) - otherwise clap treats a single
+    // occurrence as greedily consuming every following bare argument,
+    // including the input filenames, whenever the flag appears before them.
     let args = clap_app!(rjoin =>
         (version: crate_version!())
         (author: crate_authors!())
         (about: crate_description!())
-        (@arg leftField: -l --left +takes_value "Select the field to index from the left file")
-        (@arg rightField: -r --right +takes_value "Select the field to index from the right file")
-        (@arg leftAll: -L --("left-all") "Print all lines from the left file, even if they don't match")
-        (@arg rightAll: -R --("right-all") "Print all lines from the right file, even if they don't match")
-        (@arg outer: --outer "Print all lines from both files (equivalent to -LR)")
-        (@arg joinField: -j +takes_value "Select the key field for both left/right files")
-        (@arg leftFile: +required "Left file")
-        (@arg rightFile: +required "Right file")
-        (@arg leftMissing: --("left-missing") +takes_value "When using --right-all, use this value as a placeholder for any missing left columns.")
-        (@arg rightMissing: --("right-missing") +takes_value "When using --left-all, use this value as a placeholder for any missing right columns.")
+        (@arg files: +required +multiple "Input files to join (2 or more; use '-' for stdin)")
+        (@arg poly: --poly "Emit output for every key seen in any file (absent files filled with their --missing value), instead of only for keys that every file has")
         (@arg output: -o --output +takes_value "Specify output ordering of fields (join syntax)")
         (@arg delim: -t --delimiter +takes_value "Specify input/output column delimiter (default tab)")
         (@arg header: -H --header "Indicate that the input files contain a header line (will be output)")
-    ).get_matches();
+        (@arg hash: --hash "Use an in-memory hash join instead of the default sorted merge join (inputs need not be pre-sorted)")
+        (@arg quick: --quick "Assume non-driver files have no repeated keys, skipping the buffered many-to-many merge handling")
+        (@arg ignoreCase: -i --("ignore-case") "Compare key fields ignoring ASCII case")
+        (@arg numeric: --numeric "Compare key fields as numbers rather than strings")
+    )
+        .arg(Arg::with_name("key").short("k").long("key").takes_value(true).multiple(true).number_of_values(1)
+            .help("Key field for a file, as 'file:field' (1-based; default field 1 for every file); repeatable"))
+        .arg(Arg::with_name("all").short("a").long("all").takes_value(true).multiple(true).number_of_values(1)
+            .help("File number to print unmatched rows from (outer join on that file); repeatable"))
+        .arg(Arg::with_name("missing").short("m").long("missing").takes_value(true).multiple(true).number_of_values(1)
+            .help("Missing-value placeholder for a file, as 'file:value'; repeatable"))
+        .get_matches();
+
+    let filenames: Vec<&str> = args.values_of("files").unwrap().collect();
+    if filenames.len() < 2 {
+        return Err("at least 2 input files are required".into());
+    }
 
-    let mut files = vec![];
-    let dirs = vec!["left", "right"];
-    let outer = args.is_present("outer");
-    let has_header = args.is_present("header");
-    let default_join_field = args.value_of("joinField").unwrap_or("1");
     let delim = args.value_of("delim").unwrap_or("\t");
     if delim.len() != 1 {
         return Err("delimiter must be a single character".into());
     }
     let delim = delim.to_owned();
 
-    for dir in dirs {
-        let filename = args.value_of(format!("{}File", dir)).unwrap();
-        let all = args.is_present(format!("{}All", dir)) || outer;
-        let missing = args.value_of(format!("{}Missing", dir)).unwrap_or("").to_owned();
-
-        let field = args.value_of(format!("{}Field", dir)).unwrap_or(default_join_field);
-        let key_fields = parse_key_fields(field)?;
-        files.push( JoinFileConfig {
-            filename: filename.into(),
-            key_fields: key_fields,
-            all: all,
-            missing: missing,
-        } );
+    let mut key_fields: Vec<KeyFields> = vec![vec![KeyField::Indexed(0)]; filenames.len()];
+    if let Some(keys) = args.values_of("key") {
+        for key in keys {
+            let (file, field) = parse_indexed_arg(key)?;
+            if file > filenames.len() {
+                return Err(format!("--key file number {} is out of range", file).into());
+            }
+            key_fields[file - 1] = parse_key_fields(&field)?;
+        }
+    }
+
+    let mut all = vec![false; filenames.len()];
+    if let Some(files) = args.values_of("all") {
+        for file in files {
+            let file = file.trim().parse::<usize>()?;
+            if file < 1 || file > filenames.len() {
+                return Err(format!("--all file number {} is out of range", file).into());
+            }
+            all[file - 1] = true;
+        }
+    }
+
+    let mut missing = vec![String::new(); filenames.len()];
+    if let Some(specs) = args.values_of("missing") {
+        for spec in specs {
+            let (file, value) = parse_indexed_arg(spec)?;
+            if file > filenames.len() {
+                return Err(format!("--missing file number {} is out of range", file).into());
+            }
+            missing[file - 1] = value;
+        }
     }
 
+    let files: Vec<JoinFileConfig> = filenames.iter().enumerate().map(|(i, filename)| {
+        JoinFileConfig {
+            filename: (*filename).into(),
+            key_fields: key_fields[i].clone(),
+            all: all[i],
+            missing: missing[i].clone(),
+        }
+    }).collect();
+
     let output = args.value_of("output").unwrap_or("auto");
     let output = parse_output_fields(output)?;
 
-    // return the two elements as a tuple
+    let strategy = if args.is_present("hash") { JoinStrategy::Hash } else { JoinStrategy::Merge };
+
+    let comparator = if args.is_present("numeric") {
+        KeyComparator::Numeric
+    } else if args.is_present("ignoreCase") {
+        KeyComparator::IgnoreCase
+    } else {
+        KeyComparator::Raw
+    };
+
     Ok(JoinConfig {
-        left: files.remove(0),
-        right: files.remove(0),
+        files: files,
         output: output,
         output_fn: println,
         delim: delim,
-        has_header: has_header,
+        has_header: args.is_present("header"),
+        strategy: strategy,
+        quick: args.is_present("quick"),
+        comparator: comparator,
+        poly: args.is_present("poly"),
     })
 }
 
+// Parse a "file:value" argument, defaulting to file 1 when no 'file:'
+// prefix is given.
+fn parse_indexed_arg(arg: &str) -> Result<(usize, String), Box<Error>> {
+    let mut parts = arg.splitn(2, ':');
+    let first = parts.next().unwrap();
+    match parts.next() {
+        Some(value) => {
+            let file = first.trim().parse::<usize>()?;
+            if file < 1 {
+                return Err("file number must be greater than 0".into());
+            }
+            Ok((file, value.to_owned()))
+        },
+        None => Ok((1, first.to_owned())),
+    }
+}
+
 // Parse key fields (XXX re-use code from parse_output_fields)
 fn parse_key_fields(arg: &str) -> Result<KeyFields, Box<Error>> {
     let mut fields : Vec<_> = vec![];
-    
+
     for item in arg.split(',') {
         if let Ok(mut field) = item.trim().parse::<usize>() {
             if field < 1 {
@@ -104,16 +170,16 @@ fn parse_key_fields(arg: &str) -> Result<KeyFields, Box<Error>> {
             fields.push(KeyField::Named(item));
         }
     }
-    
+
     Ok(fields)
 }
 
-// Parse the file number (1, 2, left, right, l or r)
+// Parse the file number (any number >= 1, or left/right/l/r for 1/2)
 fn parse_output_field_file(arg: &str) -> Result<usize, Box<Error>> {
     let arg = arg.trim().to_lowercase();
     if let Ok(index) = arg.parse::<usize>() {
-        if index != 1 && index != 2 {
-            return Err("output field file number must be either 1 or 2".into());
+        if index < 1 {
+            return Err("output field file number must be greater than 0".into());
         }
         return Ok(index);
     }
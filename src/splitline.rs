@@ -3,28 +3,41 @@
 struct SplitLine {
     line: String,
     fields: Vec<*const str>,
-    key_field: usize,
+    key_fields: Vec<usize>,
 }
 
 impl SplitLine {
-    fn new(line: String, delim: char, key_field: usize) -> Self {
+    fn new(line: String, delim: char, key_fields: Vec<usize>) -> Self {
         let fields : Vec<*const str> = line.split(delim).map(|x| x as *const str).collect();
 
-        SplitLine { line, fields, key_field }
+        SplitLine { line, fields, key_fields }
     }
 
     fn field(&self, index: usize) -> &str {
         unsafe { &*self.fields[index] }
     }
 
-    fn key(&self) -> &str {
-        self.field(self.key_field)
+    fn num_fields(&self) -> usize {
+        self.fields.len()
+    }
+
+    // Return the values of the key fields, in key-field order.
+    fn keys(&self) -> Vec<&str> {
+        self.key_fields.iter().map(|&i| self.field(i)).collect()
     }
 
     // Return an iterable collection of &str.
     fn fields(&self) -> Vec<&str> {
         self.fields.iter().map(|x| unsafe { &**x }).collect()
     }
+
+    // Return all fields that aren't part of the key, in column order.
+    fn fields_except_keys(&self) -> Vec<&str> {
+        self.fields.iter().enumerate()
+            .filter(|&(i, _)| !self.key_fields.contains(&i))
+            .map(|(_, x)| unsafe { &**x })
+            .collect()
+    }
 }
 
 // Clone requires us to clone the underlying string. We reuse the offsets
@@ -46,7 +59,7 @@ impl Clone for SplitLine {
             }
         }).collect();
 
-        SplitLine { line: newline, fields: newfields, key_field: self.key_field }
+        SplitLine { line: newline, fields: newfields, key_fields: self.key_fields.clone() }
     }
 }
 
@@ -56,17 +69,24 @@ mod tests {
 
     #[test]
     fn basics() {
-        let s = SplitLine::new("foo\tbar\tbaz".into(), '\t', 1);
+        let s = SplitLine::new("foo\tbar\tbaz".into(), '\t', vec![1]);
         assert_eq!(s.field(0), "foo");
         assert_eq!(s.field(1), "bar");
         assert_eq!(s.field(2), "baz");
-        assert_eq!(s.key(), "bar");
+        assert_eq!(s.keys(), vec!["bar"]);
+        assert_eq!(s.fields_except_keys(), vec!["foo", "baz"]);
 
         let t = s.clone();
         assert_eq!(t.field(0), "foo");
         assert_eq!(t.field(1), "bar");
         assert_eq!(t.field(2), "baz");
-        assert_eq!(t.key(), "bar");
+        assert_eq!(t.keys(), vec!["bar"]);
     }
-}
 
+    #[test]
+    fn multi_field_key() {
+        let s = SplitLine::new("a\tb\tc\td".into(), '\t', vec![0, 2]);
+        assert_eq!(s.keys(), vec!["a", "c"]);
+        assert_eq!(s.fields_except_keys(), vec!["b", "d"]);
+    }
+}